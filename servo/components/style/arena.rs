@@ -4,16 +4,47 @@
 
 //! Arena that never frees.
 
+use smallvec::SmallVec;
+use std::alloc::Layout;
+use std::cmp;
 use std::mem;
+use std::ptr;
+use std::slice;
 
-/// Marker for types that should be arena-allocated.
+/// Marker for types that should be arena-allocated. `T` is never dropped;
+/// use `alloc_with_drop` for types with real drop glue.
 pub trait ArenaAllocated {}
 
-const CHUNK_SIZE: usize = 1 << 20;
+/// Drop glue for a type-erased `DropEntry`.
+unsafe fn drop_glue<T>(ptr: *mut u8) {
+    ptr::drop_in_place(ptr as *mut T);
+}
+
+/// A single `alloc_with_drop` allocation awaiting its destructor.
+///
+/// This raw pointer makes `Arena` lose its auto-derived `Send`/`Sync`. We
+/// don't re-derive them: whether dropping a `T` on another thread is sound
+/// depends on `T`, which we don't track here, so the compiler's opt-out is
+/// the correct default. No current caller sends an `Arena` across threads.
+struct DropEntry {
+    ptr: *mut u8,
+    drop_fn: unsafe fn(*mut u8),
+}
+
+/// Size of the first chunk.
+const FIRST_CHUNK_SIZE: usize = 1 << 12;
+
+/// Cap on `next_size`'s growth.
+const MAX_CHUNK_SIZE: usize = 1 << 21;
 
 /// Arena class.
 pub struct Arena {
     chunks: Vec<Vec<u8>>,
+    /// Capacity for the next chunk. Doubles, capped at `MAX_CHUNK_SIZE`,
+    /// each time a chunk is allocated.
+    next_size: usize,
+    /// Pending destructors from `alloc_with_drop`, in allocation order.
+    drop_entries: Vec<DropEntry>,
 }
 
 impl Arena {
@@ -21,40 +52,263 @@ impl Arena {
     pub fn new() -> Self {
         Arena {
             chunks: Vec::new(),
+            next_size: FIRST_CHUNK_SIZE,
+            drop_entries: Vec::new(),
         }
     }
 
-    /// Allocates. Chunk will never be freed.
-    pub fn allocate(&mut self, size: usize) -> *mut u8 {
-        if let Some(mut last) = self.chunks.last_mut() {
-            if last.len() + size <= last.capacity() {
-                return Self::allocate_from(&mut last, size);
+    /// Allocates `layout`, aligned. Chunk will never be freed.
+    pub fn allocate(&mut self, layout: Layout) -> *mut u8 {
+        if let Some(last) = self.chunks.last_mut() {
+            if let Some(ptr) = Self::try_allocate_from(last, layout) {
+                return ptr;
             }
         }
 
-        assert!(size < CHUNK_SIZE);
-        let mut v = Vec::with_capacity(CHUNK_SIZE);
-        let allocated = Self::allocate_from(&mut v, size);
+        // Chunks are created lazily, sized to `next_size` unless the
+        // request itself is bigger.
+        let capacity = cmp::max(self.next_size, layout.size() + layout.align());
+        let mut v = Vec::with_capacity(capacity);
+        let allocated = Self::try_allocate_from(&mut v, layout)
+            .expect("a freshly-allocated chunk must fit the request");
         self.chunks.push(v);
+        self.next_size = cmp::min(self.next_size * 2, MAX_CHUNK_SIZE);
         allocated
     }
 
-    fn allocate_from(v: &mut Vec<u8>, size: usize) -> *mut u8 {
-        let new_len = v.len() + size;
-        debug_assert!(new_len <= v.capacity());
+    /// Tries to bump-allocate `layout` out of `v`, padding for alignment.
+    /// Returns `None` if `v` doesn't have enough spare capacity.
+    fn try_allocate_from(v: &mut Vec<u8>, layout: Layout) -> Option<*mut u8> {
         unsafe {
-            let allocated = v.as_mut_ptr().offset(v.len() as isize);
+            let base = v.as_mut_ptr();
+            let cur = base.add(v.len()) as usize;
+            let align = layout.align();
+            let pad = (align - (cur % align)) % align;
+            let new_len = v.len() + pad + layout.size();
+            if new_len > v.capacity() {
+                return None;
+            }
+            let allocated = base.add(v.len() + pad);
             v.set_len(new_len);
-            allocated
+            Some(allocated)
+        }
+    }
+
+    /// Allocates `value` in the arena. `T` is never dropped.
+    pub fn alloc<T: ArenaAllocated>(&mut self, value: T) -> &mut T {
+        unsafe {
+            let ptr = self.allocate(Layout::new::<T>()) as *mut T;
+            ptr::write(ptr, value);
+            &mut *ptr
+        }
+    }
+
+    /// Copies `src` into the arena as one contiguous allocation.
+    pub fn alloc_slice<T: Copy>(&mut self, src: &[T]) -> &mut [T] {
+        if src.is_empty() {
+            return &mut [];
+        }
+        if mem::size_of::<T>() == 0 {
+            return unsafe {
+                slice::from_raw_parts_mut(ptr::NonNull::<T>::dangling().as_ptr(), src.len())
+            };
+        }
+
+        unsafe {
+            let layout = Layout::array::<T>(src.len()).unwrap();
+            let ptr = self.allocate(layout) as *mut T;
+            ptr::copy_nonoverlapping(src.as_ptr(), ptr, src.len());
+            slice::from_raw_parts_mut(ptr, src.len())
+        }
+    }
+
+    /// Collects `iter` into the arena as one contiguous allocation.
+    pub fn alloc_from_iter<T: Copy, I>(&mut self, iter: I) -> &mut [T]
+    where
+        I: IntoIterator<Item = T>,
+    {
+        let mut vec: SmallVec<[T; 8]> = iter.into_iter().collect();
+        if vec.is_empty() {
+            return &mut [];
+        }
+        if mem::size_of::<T>() == 0 {
+            return unsafe {
+                slice::from_raw_parts_mut(ptr::NonNull::<T>::dangling().as_ptr(), vec.len())
+            };
+        }
+
+        unsafe {
+            let len = vec.len();
+            let layout = Layout::array::<T>(len).unwrap();
+            let ptr = self.allocate(layout) as *mut T;
+            ptr::copy_nonoverlapping(vec.as_ptr(), ptr, len);
+            vec.set_len(0);
+            slice::from_raw_parts_mut(ptr, len)
+        }
+    }
+
+    /// Allocates `value` like `alloc`, but actually drops it on `Drop`.
+    pub fn alloc_with_drop<T>(&mut self, value: T) -> &mut T {
+        unsafe {
+            let ptr = self.allocate(Layout::new::<T>()) as *mut T;
+            ptr::write(ptr, value);
+            self.drop_entries.push(DropEntry {
+                ptr: ptr as *mut u8,
+                drop_fn: drop_glue::<T>,
+            });
+            &mut *ptr
         }
     }
 }
 
 impl Drop for Arena {
     fn drop(&mut self) {
+        // Run destructors before the backing memory goes away.
+        for entry in self.drop_entries.drain(..).rev() {
+            unsafe {
+                (entry.drop_fn)(entry.ptr);
+            }
+        }
+
         for mut v in self.chunks.drain(..) {
             v.shrink_to_fit();
             mem::forget(v);
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+
+    #[repr(align(64))]
+    #[derive(Copy, Clone)]
+    struct OverAligned(u64);
+    impl ArenaAllocated for OverAligned {}
+
+    struct DropRecorder<'a> {
+        id: u32,
+        log: &'a RefCell<Vec<u32>>,
+    }
+
+    impl<'a> Drop for DropRecorder<'a> {
+        fn drop(&mut self) {
+            self.log.borrow_mut().push(self.id);
+        }
+    }
+
+    #[test]
+    fn alloc_with_drop_runs_the_destructor() {
+        let log = RefCell::new(Vec::new());
+        {
+            let mut arena = Arena::new();
+            arena.alloc_with_drop(DropRecorder { id: 1, log: &log });
+        }
+        assert_eq!(*log.borrow(), vec![1]);
+    }
+
+    #[test]
+    fn alloc_with_drop_runs_in_reverse_allocation_order() {
+        let log = RefCell::new(Vec::new());
+        {
+            let mut arena = Arena::new();
+            arena.alloc_with_drop(DropRecorder { id: 1, log: &log });
+            arena.alloc_with_drop(DropRecorder { id: 2, log: &log });
+            arena.alloc_with_drop(DropRecorder { id: 3, log: &log });
+        }
+        assert_eq!(*log.borrow(), vec![3, 2, 1]);
+    }
+
+    #[test]
+    fn alloc_with_drop_runs_across_chunk_rollover_and_oversized_chunk() {
+        let log = RefCell::new(Vec::new());
+        {
+            let mut arena = Arena::new();
+            arena.alloc_with_drop(DropRecorder { id: 1, log: &log });
+            // Force a normal chunk rollover between drop-tracked allocations.
+            arena.alloc_slice(&vec![0u8; FIRST_CHUNK_SIZE]);
+            arena.alloc_with_drop(DropRecorder { id: 2, log: &log });
+            // Force the oversized-chunk path too.
+            arena.alloc_slice(&vec![0u8; MAX_CHUNK_SIZE * 2]);
+            arena.alloc_with_drop(DropRecorder { id: 3, log: &log });
+        }
+        assert_eq!(*log.borrow(), vec![3, 2, 1]);
+    }
+
+    #[test]
+    fn oversized_allocation_fits_in_its_own_chunk() {
+        let mut arena = Arena::new();
+        let src = vec![0u8; MAX_CHUNK_SIZE * 2];
+        let huge = arena.alloc_slice(&src);
+        assert_eq!(huge.len(), MAX_CHUNK_SIZE * 2);
+        huge[0] = 1;
+        huge[huge.len() - 1] = 2;
+        assert_eq!(huge[0], 1);
+        assert_eq!(huge[huge.len() - 1], 2);
+    }
+
+    #[test]
+    fn alloc_slice_basic_and_edge_cases() {
+        let mut arena = Arena::new();
+
+        let empty_src: &[u32] = &[];
+        let empty = arena.alloc_slice(empty_src);
+        assert!(empty.is_empty());
+
+        let zst = arena.alloc_slice(&[(), (), ()]);
+        assert_eq!(zst.len(), 3);
+
+        let copied = arena.alloc_slice(&[1u32, 2, 3]);
+        assert_eq!(copied, &[1, 2, 3]);
+    }
+
+    #[test]
+    fn alloc_from_iter_basic_and_edge_cases() {
+        let mut arena = Arena::new();
+
+        let empty: &mut [u32] = arena.alloc_from_iter(std::iter::empty());
+        assert!(empty.is_empty());
+
+        let zst = arena.alloc_from_iter(vec![(), (), ()]);
+        assert_eq!(zst.len(), 3);
+
+        let collected = arena.alloc_from_iter(vec![1u32, 2, 3]);
+        assert_eq!(collected, &[1, 2, 3]);
+    }
+
+    #[test]
+    fn next_size_grows_geometrically_and_caps() {
+        let mut arena = Arena::new();
+        assert_eq!(arena.next_size, FIRST_CHUNK_SIZE);
+
+        arena.allocate(Layout::from_size_align(1, 1).unwrap());
+        assert_eq!(arena.chunks.len(), 1);
+        assert_eq!(arena.chunks[0].capacity(), FIRST_CHUNK_SIZE);
+        assert_eq!(arena.next_size, FIRST_CHUNK_SIZE * 2);
+
+        // Requesting the whole capacity of the current chunk never fits
+        // (it's already non-empty), so this always rolls over to a new,
+        // bigger chunk.
+        while arena.next_size < MAX_CHUNK_SIZE {
+            let size = arena.chunks.last().unwrap().capacity();
+            let prev_next_size = arena.next_size;
+            arena.allocate(Layout::array::<u8>(size).unwrap());
+            assert_eq!(arena.chunks.last().unwrap().capacity(), prev_next_size);
+        }
+
+        assert_eq!(arena.next_size, MAX_CHUNK_SIZE);
+        let size = arena.chunks.last().unwrap().capacity();
+        arena.allocate(Layout::array::<u8>(size).unwrap());
+        assert_eq!(arena.next_size, MAX_CHUNK_SIZE);
+    }
+
+    #[test]
+    fn allocations_are_aligned_even_after_an_odd_sized_allocation() {
+        let mut arena = Arena::new();
+        arena.alloc_slice(&[0u8; 3]);
+        let value = arena.alloc(OverAligned(0xdead_beef));
+        assert_eq!(value as *const OverAligned as usize % mem::align_of::<OverAligned>(), 0);
+        assert_eq!(value.0, 0xdead_beef);
+    }
+}